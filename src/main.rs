@@ -1,12 +1,17 @@
 use chrono::{DateTime, Duration, NaiveDateTime, Utc};
+use crypto_secretbox::aead::{Aead, AeadCore, KeyInit, OsRng};
+use crypto_secretbox::{Nonce, XSalsa20Poly1305};
 use log::{info, warn};
 use reqwest::StatusCode;
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use std::env;
 use std::fs::File;
+use std::hash::Hash;
 use std::io::{Read, Write};
 
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Mutex, RwLock};
+use tokio::sync::watch;
+use tracing::{field, Span};
 use warp::http::Response;
 use warp::path::Tail;
 use warp::Filter;
@@ -14,10 +19,19 @@ use warp::Filter;
 use borsh::{BorshDeserialize, BorshSerialize};
 use reqwest::header::REFERER;
 
+use metrics::{Metrics, SharedMetrics};
+
+mod metrics;
+mod token;
+
 const MAGIC_CACHE_DURATION_SECONDS: i64 = 1 * 60 * 60;
 const REGULAR_CACHE_DURATION_SECONDS: i64 = 30 * 24 * 60 * 60;
 const MAX_REFRESH_TIMEOUT: u64 = 60 * 60;
 const PURGE_MAGIC_KEYWORD: &str = "purge";
+const DISK_JANITOR_INTERVAL_SECONDS: u64 = 5 * 60;
+const CACHE_DIR: &str = "cache";
+const HTTP_POOL_IDLE_TIMEOUT_SECONDS: u64 = 90;
+const SECRETBOX_NONCE_LEN: usize = 24;
 
 #[derive(Debug, PartialEq, Copy, Clone, Eq, Hash, BorshSerialize, BorshDeserialize)]
 pub enum ImgType {
@@ -74,8 +88,224 @@ pub enum CachedMagicUrl {
 }
 
 type ImgPair = (ImgType, String);
-type MagicCache = Arc<Mutex<HashMap<String, CachedMagicUrl>>>;
-type ImgCache = Arc<Mutex<HashMap<ImgPair, CachedImage>>>;
+type MagicCache = Arc<Mutex<LruCache<String, CachedMagicUrl>>>;
+type ImgCache = Arc<Mutex<LruCache<ImgPair, CachedImage>>>;
+
+/// Byte cost of a cache entry, used to keep [`LruCache`] under its budget.
+/// Only successful entries carry real weight; failed attempts are tiny and
+/// don't count against the budget.
+trait CacheWeight {
+    fn cache_weight(&self) -> u64;
+}
+
+impl CacheWeight for CachedImage {
+    fn cache_weight(&self) -> u64 {
+        match self {
+            CachedImage::Success { image, .. } => {
+                (image.content_type.len() + image.body.len()) as u64
+            }
+            CachedImage::Failed { .. } => 0,
+        }
+    }
+}
+
+impl CacheWeight for CachedMagicUrl {
+    fn cache_weight(&self) -> u64 {
+        match self {
+            CachedMagicUrl::Success { url, .. } => url.len() as u64,
+            CachedMagicUrl::Failed { .. } => 0,
+        }
+    }
+}
+
+/// A `HashMap` bounded by total byte weight, evicting least-recently-used
+/// entries to stay under `max_bytes`. A `max_bytes` of `0` means unbounded,
+/// matching the pre-existing behavior when no budget is configured. A single
+/// entry heavier than `max_bytes` is still inserted, after every other entry
+/// has been evicted, so the total then exceeds the budget until it's replaced.
+struct LruCache<K, V> {
+    entries: HashMap<K, V>,
+    order: VecDeque<K>,
+    total_bytes: u64,
+    max_bytes: u64,
+}
+
+impl<K: Eq + Hash + Clone, V: CacheWeight + Clone> LruCache<K, V> {
+    fn new(max_bytes: u64) -> Self {
+        Self {
+            entries: HashMap::new(),
+            order: VecDeque::new(),
+            total_bytes: 0,
+            max_bytes,
+        }
+    }
+
+    fn touch(&mut self, key: &K) {
+        if let Some(pos) = self.order.iter().position(|k| k == key) {
+            let key = self.order.remove(pos).unwrap();
+            self.order.push_back(key);
+        }
+    }
+
+    fn get(&mut self, key: &K) -> Option<V> {
+        let value = self.entries.get(key).cloned();
+        if value.is_some() {
+            self.touch(key);
+        }
+        value
+    }
+
+    fn remove(&mut self, key: &K) -> Option<V> {
+        self.order.retain(|k| k != key);
+        let value = self.entries.remove(key);
+        if let Some(value) = &value {
+            self.total_bytes = self.total_bytes.saturating_sub(value.cache_weight());
+        }
+        value
+    }
+
+    fn insert(&mut self, key: K, value: V) {
+        self.remove(&key);
+        let weight = value.cache_weight();
+        self.evict_to_fit(weight);
+        self.total_bytes += weight;
+        self.order.push_back(key.clone());
+        self.entries.insert(key, value);
+    }
+
+    fn evict_to_fit(&mut self, incoming_weight: u64) {
+        if self.max_bytes == 0 {
+            return;
+        }
+        while self.total_bytes + incoming_weight > self.max_bytes {
+            let Some(lru_key) = self.order.pop_front() else {
+                break;
+            };
+            if let Some(value) = self.entries.remove(&lru_key) {
+                self.total_bytes = self.total_bytes.saturating_sub(value.cache_weight());
+            }
+        }
+    }
+}
+
+/// Last-access metadata for a file under [`CACHE_DIR`], keyed by its path.
+/// Rebuilt from disk on startup and kept up to date by reads and writes so
+/// the background janitor can evict cold entries without re-scanning.
+#[derive(Debug, Clone)]
+struct DiskCacheEntry {
+    size: u64,
+    last_access: DateTime<Utc>,
+}
+
+type DiskIndex = Arc<Mutex<HashMap<String, DiskCacheEntry>>>;
+
+fn max_bytes_from_env(name: &str) -> u64 {
+    env::var(name)
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(0)
+}
+
+/// Builds the single `reqwest::Client` shared by every upstream fetch, so
+/// the connection pool and TLS sessions are reused across requests instead
+/// of being paid for on every call. Behavior is tuned via env vars:
+/// `UPSTREAM_PROXY` routes upstream traffic through a proxy (e.g. for
+/// fetching from rescale backends behind a VPN), and
+/// `ACCEPT_INVALID_UPSTREAM_CERTS=1` disables TLS verification for
+/// self-signed rescale backends.
+fn build_http_client() -> reqwest::Client {
+    let mut builder = reqwest::Client::builder()
+        .pool_idle_timeout(std::time::Duration::from_secs(
+            HTTP_POOL_IDLE_TIMEOUT_SECONDS,
+        ))
+        .https_only(env::var_os("UPSTREAM_HTTPS_ONLY").is_some());
+
+    if let Some(proxy_url) = env::var_os("UPSTREAM_PROXY") {
+        let proxy_url = proxy_url
+            .to_str()
+            .expect("UPSTREAM_PROXY must be valid UTF-8");
+        let proxy = reqwest::Proxy::all(proxy_url).expect("Invalid UPSTREAM_PROXY");
+        builder = builder.proxy(proxy);
+    }
+
+    if env::var_os("ACCEPT_INVALID_UPSTREAM_CERTS").is_some() {
+        builder = builder.danger_accept_invalid_certs(true);
+    }
+
+    builder.build().expect("Failed to build HTTP client")
+}
+
+/// Cipher used to encrypt cache entries at rest, shared by every disk read
+/// and write. `None` when `CACHE_ENCRYPTION_KEY` isn't configured, in which
+/// case the cache falls back to the original plaintext format.
+type CacheCipher = Option<Arc<XSalsa20Poly1305>>;
+
+/// Builds the cache-at-rest cipher from `CACHE_ENCRYPTION_KEY` (32 bytes,
+/// hex or base64), or returns `None` if the env var isn't set.
+fn build_cache_cipher() -> CacheCipher {
+    let key = env::var("CACHE_ENCRYPTION_KEY").ok()?;
+    let key_bytes = decode_cache_encryption_key(&key)
+        .expect("CACHE_ENCRYPTION_KEY must be 32 bytes of hex or base64");
+    Some(Arc::new(XSalsa20Poly1305::new_from_slice(&key_bytes).unwrap()))
+}
+
+fn decode_cache_encryption_key(value: &str) -> Option<[u8; 32]> {
+    decode_hex_or_base64(value)?.try_into().ok()
+}
+
+fn decode_hex_or_base64(value: &str) -> Option<Vec<u8>> {
+    hex::decode(value).ok().or_else(|| base64::decode(value).ok())
+}
+
+/// Secret used to verify signed-token URLs, shared by every request.
+/// When [`build_token_secret`] returns `None` instead, the access-control
+/// mode is disabled and the original open-proxy behavior is preserved.
+type TokenSecret = Arc<Vec<u8>>;
+
+/// Builds the token-verification secret from `TOKEN_SIGNING_SECRET` (hex or
+/// base64), or returns `None` if the env var isn't set. This gates the
+/// access-control feature, so a present-but-malformed value must panic
+/// rather than silently falling back to open-proxy mode.
+fn build_token_secret() -> Option<TokenSecret> {
+    let value = env::var_os("TOKEN_SIGNING_SECRET")?;
+    let value = value
+        .to_str()
+        .expect("TOKEN_SIGNING_SECRET must be valid UTF-8");
+    let secret = decode_hex_or_base64(value).expect("TOKEN_SIGNING_SECRET must be hex or base64");
+    Some(Arc::new(secret))
+}
+
+/// State of an in-flight, single-flight upstream fetch shared by every
+/// caller that asked for the same key while it was running.
+#[derive(Debug, Clone)]
+enum FetchState<T> {
+    Processing,
+    Done(T),
+}
+
+type ImgFetchResult = Result<Image, FetchError>;
+type MagicFetchResult = Result<(String, StatusCode), FetchError>;
+
+/// Pending upstream fetches keyed by the thing being fetched, so that
+/// concurrent requests for the same key coalesce into a single upstream
+/// call instead of hammering the rescale backend.
+type ImgLocks = Arc<RwLock<HashMap<ImgPair, watch::Receiver<FetchState<ImgFetchResult>>>>>;
+type MagicLocks = Arc<RwLock<HashMap<String, watch::Receiver<FetchState<MagicFetchResult>>>>>;
+
+/// Shared, rarely-varying state for a proxy request, bundled behind one
+/// `Arc` so the warp filter chain carries a single clone instead of one
+/// `warp::any().map(...)` per field, and downstream functions take one
+/// parameter instead of `clippy::too_many_arguments` worth of them.
+struct AppState {
+    imgs: ImgCache,
+    magic: MagicCache,
+    img_locks: ImgLocks,
+    magic_locks: MagicLocks,
+    disk_index: DiskIndex,
+    http_client: reqwest::Client,
+    cache_cipher: CacheCipher,
+    metrics: SharedMetrics,
+}
 
 #[derive(Debug, Clone, BorshSerialize, BorshDeserialize)]
 pub struct SavedImage {
@@ -89,6 +319,88 @@ pub fn sha256(data: &[u8]) -> [u8; 32] {
     sha2::Sha256::digest(data).into()
 }
 
+/// Hex-encoded digest of a URL, used as a span field so traces can be
+/// correlated across log lines without leaking the URL itself.
+fn url_hash_hex(url: &str) -> String {
+    hex::encode(sha256(url.as_bytes()))
+}
+
+/// Which side of a [`coalesce`] call a caller ended up on, decided while
+/// still holding the lock so the get-or-insert is atomic.
+enum Role<T> {
+    Lead(watch::Sender<FetchState<T>>),
+    Follow(watch::Receiver<FetchState<T>>),
+}
+
+/// Runs `fetch` at most once per `key` across concurrent callers: the first
+/// caller to see a missing entry becomes the leader and performs `fetch`,
+/// while every other caller for the same key waits on a `watch` receiver and
+/// is handed the leader's result once it's done. The lock entry is always
+/// removed and a terminal value always sent, even when `fetch` resolves to
+/// an error, so waiters are never left hanging. If the leader is dropped
+/// before its fetch finishes, the entry is still removed and each waiting
+/// caller runs `fetch` itself.
+async fn coalesce<K, T, Fut>(
+    key: K,
+    locks: &Arc<RwLock<HashMap<K, watch::Receiver<FetchState<T>>>>>,
+    fetch: impl FnOnce() -> Fut,
+) -> T
+where
+    K: Hash + Eq + Clone,
+    T: Clone,
+    Fut: std::future::Future<Output = T>,
+{
+    // The lookup and the insert must happen under the same lock acquisition,
+    // or two concurrent callers can both see a missing entry and both become
+    // leaders. The guard itself must not be held across an `.await` (it's not
+    // `Send`), so the role is decided here and the guard is dropped before
+    // any awaiting happens below.
+    let role = {
+        let mut map = locks.write().unwrap();
+        match map.get(&key) {
+            Some(rx) => Role::Follow(rx.clone()),
+            None => {
+                let (tx, rx) = watch::channel(FetchState::Processing);
+                map.insert(key.clone(), rx);
+                Role::Lead(tx)
+            }
+        }
+    };
+
+    match role {
+        Role::Follow(mut rx) => {
+            while matches!(*rx.borrow(), FetchState::Processing) {
+                if rx.changed().await.is_err() {
+                    break;
+                }
+            }
+            let state = rx.borrow().clone();
+            match state {
+                FetchState::Done(result) => result,
+                // The leader was dropped mid-fetch without sending `Done`.
+                FetchState::Processing => fetch().await,
+            }
+        }
+        Role::Lead(tx) => {
+            // Removes the lock entry on drop, so it also goes away when this
+            // future is dropped before `fetch` completes.
+            struct Unlock<'a, K: Hash + Eq, T> {
+                key: &'a K,
+                locks: &'a Arc<RwLock<HashMap<K, watch::Receiver<FetchState<T>>>>>,
+            }
+            impl<K: Hash + Eq, T> Drop for Unlock<'_, K, T> {
+                fn drop(&mut self) {
+                    self.locks.write().unwrap().remove(self.key);
+                }
+            }
+            let _unlock = Unlock { key: &key, locks };
+            let result = fetch().await;
+            let _ = tx.send(FetchState::Done(result.clone()));
+            result
+        }
+    }
+}
+
 #[tokio::main]
 async fn main() {
     if env::var_os("IMAGE_RESCALE_URL_Thumbnail").is_none()
@@ -96,74 +408,140 @@ async fn main() {
     {
         panic!("Env IMAGE_RESCALE_URL_Thumbnail and IMAGE_RESCALE_URL_Large are required");
     }
-    env_logger::init();
+    // Without a `tracing` subscriber installed, every `#[tracing::instrument]`
+    // span and `tracing::info!`/`warn!` event added for request correlation
+    // is silently dropped. `tracing_log` bridges the remaining `log::info!`/
+    // `warn!` call sites into the same subscriber, replacing `env_logger` so
+    // there's a single place logs and spans end up.
+    tracing_log::LogTracer::init().expect("tracing_log::LogTracer can only be installed once");
+    tracing_subscriber::fmt::init();
 
-    let imgs: ImgCache = Arc::new(Mutex::new(HashMap::new()));
-    let imgs = warp::any().map(move || imgs.clone());
+    let max_mem_cache_bytes = max_bytes_from_env("MAX_MEM_CACHE_BYTES");
 
-    let magic: MagicCache = Arc::new(Mutex::new(HashMap::new()));
-    let magic = warp::any().map(move || magic.clone());
+    let disk_index: DiskIndex = Arc::new(Mutex::new(scan_disk_cache()));
+    tokio::spawn(disk_cache_janitor(disk_index.clone()));
+
+    let state = Arc::new(AppState {
+        imgs: Arc::new(Mutex::new(LruCache::new(max_mem_cache_bytes))),
+        magic: Arc::new(Mutex::new(LruCache::new(max_mem_cache_bytes))),
+        img_locks: Arc::new(RwLock::new(HashMap::new())),
+        magic_locks: Arc::new(RwLock::new(HashMap::new())),
+        disk_index,
+        http_client: build_http_client(),
+        cache_cipher: build_cache_cipher(),
+        metrics: Arc::new(Metrics::default()),
+    });
+    let metrics_route_state = state.metrics.clone();
+    let state_filter = warp::any().map(move || state.clone());
 
     let cors = warp::cors().allow_any_origin();
     let log = warp::log("warp");
 
-    let proxy =
-        warp::path!(String / ..)
+    let query = warp::filters::query::raw()
+        .map(|q| Some(q))
+        .or(warp::any().map(|| None))
+        .unify();
+
+    let proxy = if let Some(token_secret) = build_token_secret() {
+        warp::path!(String / String / ..)
             .and(warp::path::tail())
-            .and(
-                warp::filters::query::raw()
-                    .map(|q| Some(q))
-                    .or(warp::any().map(|| None))
-                    .unify(),
-            )
-            .and(imgs)
-            .and(magic)
+            .and(query)
+            .and(state_filter.clone())
             .and_then(
-                |img_type,
-                 img_path: Tail,
-                 query: Option<String>,
-                 imgs: ImgCache,
-                 magic: MagicCache| async move {
-                    let url = if let Some(query) = query {
-                        format!("{}?{}", img_path.as_str(), query)
-                    } else {
-                        img_path.as_str().to_string()
-                    };
-                    match proxy_img(img_type, url, imgs, magic).await {
-                        Ok(ImageWithCacheDuration {
-                            image: Image { content_type, body },
-                            cache_duration_seconds,
-                        }) => Ok(Response::builder()
-                            .header("content-type", content_type)
-                            .header(
-                                "Cache-Control",
-                                format!("public,max-age={cache_duration_seconds}"),
-                            )
-                            .body(body)),
-                        Err(e) => match e {
-                            FetchError::Purge => Ok(Response::builder()
+                move |request_token: String,
+                      img_type: String,
+                      img_path: Tail,
+                      query: Option<String>,
+                      state: Arc<AppState>| {
+                    let token_secret = token_secret.clone();
+                    async move {
+                        let url = request_url(img_path, query);
+                        if token::verify(&token_secret, &request_token, &img_type, &url).is_err()
+                        {
+                            return Ok(Response::builder()
+                                .status(403)
                                 .header("content-type", "text/plain")
-                                .body("Purged".as_bytes().to_vec())),
-                            _ => Err(warp::reject::reject()),
-                        },
+                                .body("Forbidden".as_bytes().to_vec()));
+                        }
+                        build_proxy_response(img_type, url, state).await
                     }
                 },
             )
             .with(cors.clone())
-            .with(log);
+            .with(log.clone())
+            .boxed()
+    } else {
+        warp::path!(String / ..)
+            .and(warp::path::tail())
+            .and(query)
+            .and(state_filter.clone())
+            .and_then(
+                |img_type, img_path: Tail, query: Option<String>, state: Arc<AppState>| async move {
+                    let url = request_url(img_path, query);
+                    build_proxy_response(img_type, url, state).await
+                },
+            )
+            .with(cors.clone())
+            .with(log.clone())
+            .boxed()
+    };
+
+    let metrics_route = warp::path!("metrics")
+        .map(move || {
+            Response::builder()
+                .header("content-type", "text/plain; version=0.0.4")
+                .body(metrics_route_state.render().into_bytes())
+        })
+        .with(log.clone())
+        .boxed();
 
     let port: u16 = env::var_os("PORT")
         .map(|port| port.to_str().unwrap().parse().unwrap())
         .unwrap_or(3030);
 
-    warp::serve(proxy).run(([127, 0, 0, 1], port)).await;
+    warp::serve(metrics_route.or(proxy))
+        .run(([127, 0, 0, 1], port))
+        .await;
 }
 
+fn request_url(img_path: Tail, query: Option<String>) -> String {
+    if let Some(query) = query {
+        format!("{}?{}", img_path.as_str(), query)
+    } else {
+        img_path.as_str().to_string()
+    }
+}
+
+async fn build_proxy_response(
+    img_type: String,
+    url: String,
+    state: Arc<AppState>,
+) -> Result<Result<Response<Vec<u8>>, warp::http::Error>, warp::Rejection> {
+    match proxy_img(img_type, url, state).await {
+        Ok(ImageWithCacheDuration {
+            image: Image { content_type, body },
+            cache_duration_seconds,
+        }) => Ok(Response::builder()
+            .header("content-type", content_type)
+            .header(
+                "Cache-Control",
+                format!("public,max-age={cache_duration_seconds}"),
+            )
+            .body(body)),
+        Err(e) => match e {
+            FetchError::Purge => Ok(Response::builder()
+                .header("content-type", "text/plain")
+                .body("Purged".as_bytes().to_vec())),
+            _ => Err(warp::reject::reject()),
+        },
+    }
+}
+
+#[tracing::instrument(skip_all, fields(img_type = %img_type, url_hash = %url_hash_hex(&url), outcome = field::Empty))]
 async fn proxy_img(
     mut img_type: String,
     mut url: String,
-    imgs: ImgCache,
-    magic: MagicCache,
+    state: Arc<AppState>,
 ) -> Result<ImageWithCacheDuration, FetchError> {
     let is_magic = img_type == "magic";
     if is_magic {
@@ -175,7 +553,7 @@ async fn proxy_img(
         }
     }
     if img_type == PURGE_MAGIC_KEYWORD {
-        let _magic_url = magic.lock().unwrap().remove(&url);
+        let _magic_url = state.magic.lock().unwrap().remove(&url);
         return Err(FetchError::Purge);
     }
     let img_type = match img_type.as_str() {
@@ -185,7 +563,14 @@ async fn proxy_img(
     };
     let mut cache_duration_seconds = REGULAR_CACHE_DURATION_SECONDS;
     if is_magic {
-        let (resolved_url, status) = resolve_magic_url(url, magic).await?;
+        let (resolved_url, status) = resolve_magic_url(
+            url,
+            state.magic.clone(),
+            state.magic_locks.clone(),
+            state.http_client.clone(),
+            state.metrics.clone(),
+        )
+        .await?;
         cache_duration_seconds = if status.as_u16() == 200 {
             MAGIC_CACHE_DURATION_SECONDS
         } else {
@@ -214,41 +599,84 @@ async fn proxy_img(
     }
 
     let pair = (img_type, url);
-    let img = imgs.lock().unwrap().get(&pair).cloned();
-    let mut attempts = if let Some(img) = img {
-        info!(target: "cache", "Retrieving from cache {:?} {}", pair.0, pair.1);
+    let img = state.imgs.lock().unwrap().get(&pair);
+    let attempts = if let Some(img) = img {
         match img {
             CachedImage::Failed { err, attempts } => {
                 let now = Utc::now();
                 let num_attempts = attempts.len() as u32;
-                warn!(target: "cache", "Failed attempts {}", num_attempts);
+                tracing::warn!(attempts = num_attempts, "found cached failed attempts");
                 let timeout = Duration::seconds(std::cmp::min(
                     MAX_REFRESH_TIMEOUT,
                     2u64.pow(num_attempts - 1),
                 ) as _);
                 let duration = now.signed_duration_since(attempts.last().unwrap().clone());
                 if duration < timeout {
+                    Span::current().record("outcome", "backoff");
+                    state.metrics.record_backoff();
                     return Err(err);
                 }
                 attempts
             }
             CachedImage::Success { image, .. } => {
+                Span::current().record("outcome", "cache_hit");
+                state.metrics.record_cache_hit();
+                tracing::info!("retrieving from memory cache");
                 return Ok(ImageWithCacheDuration {
                     image,
                     cache_duration_seconds,
-                })
+                });
             }
         }
     } else {
-        if let Some(saved_image) = read_from_disk(&pair) {
-            info!(target: "cache", "Retrieving from disk {:?} {}", pair.0, pair.1);
+        if let Some(saved_image) = read_from_disk(&pair, &state.disk_index, &state.cache_cipher) {
+            Span::current().record("outcome", "disk_hit");
+            state.metrics.record_disk_hit();
+            tracing::info!("retrieving from disk cache");
             return Ok(ImageWithCacheDuration {
-                image: cache_and_return(imgs, saved_image),
+                image: cache_and_return(state.imgs.clone(), saved_image),
                 cache_duration_seconds,
             });
         }
         vec![]
     };
+
+    Span::current().record("outcome", "cache_miss");
+    state.metrics.record_cache_miss();
+    let res = coalesce(pair.clone(), &state.img_locks, || {
+        fetch_and_cache_img(
+            pair.clone(),
+            state.imgs.clone(),
+            attempts.clone(),
+            state.disk_index.clone(),
+            state.http_client.clone(),
+            state.cache_cipher.clone(),
+            state.metrics.clone(),
+        )
+    })
+    .await;
+    match res {
+        Ok(image) => Ok(ImageWithCacheDuration {
+            image,
+            cache_duration_seconds,
+        }),
+        Err(err) => Err(err),
+    }
+}
+
+/// Performs the actual upstream fetch for a cache miss and updates `imgs`
+/// accordingly. Only ever run once per `pair` at a time; concurrent misses
+/// are folded together by [`coalesce`].
+#[tracing::instrument(skip_all, fields(img_type = ?pair.0, url_hash = %url_hash_hex(&pair.1)))]
+async fn fetch_and_cache_img(
+    pair: ImgPair,
+    imgs: ImgCache,
+    mut attempts: Vec<DateTime<Utc>>,
+    disk_index: DiskIndex,
+    http_client: reqwest::Client,
+    cache_cipher: CacheCipher,
+    metrics: SharedMetrics,
+) -> ImgFetchResult {
     let url = format!(
         "{}/{}",
         env::var_os(format!("IMAGE_RESCALE_URL_{:?}", pair.0))
@@ -257,15 +685,13 @@ async fn proxy_img(
             .unwrap(),
         pair.1
     );
-    let res = fetch_img(url).await;
-    info!(target: "cache", "Caching {:?} {}", pair.0, pair.1);
+    let res = fetch_img(url, http_client, metrics).await;
+    tracing::info!("caching fetched image");
     match res {
         Ok(image) => {
-            let saved_image = write_to_disk(pair, image).expect("Failed to save to disk");
-            Ok(ImageWithCacheDuration {
-                image: cache_and_return(imgs, saved_image),
-                cache_duration_seconds,
-            })
+            let saved_image = write_to_disk(pair, image, &disk_index, &cache_cipher)
+                .expect("Failed to save to disk");
+            Ok(cache_and_return(imgs, saved_image))
         }
         Err(err) => {
             attempts.push(Utc::now());
@@ -281,24 +707,29 @@ async fn proxy_img(
     }
 }
 
+#[tracing::instrument(skip_all, fields(url_hash = %url_hash_hex(&url), outcome = field::Empty))]
 async fn resolve_magic_url(
     url: String,
     magic: MagicCache,
+    magic_locks: MagicLocks,
+    http_client: reqwest::Client,
+    metrics: SharedMetrics,
 ) -> Result<(String, StatusCode), FetchError> {
-    let magic_url = magic.lock().unwrap().get(&url).cloned();
+    let magic_url = magic.lock().unwrap().get(&url);
     let attempts = if let Some(magic_url) = magic_url {
-        info!(target: "cache", "Retrieving from magic cache {}", url);
         match magic_url {
             CachedMagicUrl::Failed { err, attempts } => {
                 let now = Utc::now();
                 let num_attempts = attempts.len() as u32;
-                warn!(target: "cache", "Failed attempts {}", num_attempts);
+                tracing::warn!(attempts = num_attempts, "found cached failed attempts");
                 let timeout = Duration::seconds(std::cmp::min(
                     MAX_REFRESH_TIMEOUT,
                     2u64.pow(num_attempts - 1),
                 ) as _);
                 let duration = now.signed_duration_since(attempts.last().unwrap().clone());
                 if duration < timeout {
+                    Span::current().record("outcome", "backoff");
+                    metrics.record_backoff();
                     return Err(err);
                 }
                 attempts
@@ -308,11 +739,26 @@ async fn resolve_magic_url(
                 status,
                 time,
             } => {
+                Span::current().record("outcome", "cache_hit");
+                tracing::info!("retrieving from magic cache");
                 let now = Utc::now();
                 let duration = now.signed_duration_since(time);
                 if duration > Duration::seconds(MAGIC_CACHE_DURATION_SECONDS) {
+                    let magic = magic.clone();
+                    let magic_locks = magic_locks.clone();
+                    let http_client = http_client.clone();
+                    let metrics = metrics.clone();
                     tokio::spawn(async move {
-                        let _res = magic_fetch_and_cache(url, magic, vec![]).await;
+                        let _res = coalesce(url.clone(), &magic_locks, || {
+                            magic_fetch_and_cache(
+                                url.clone(),
+                                magic.clone(),
+                                vec![],
+                                http_client,
+                                metrics,
+                            )
+                        })
+                        .await;
                     });
                 }
                 return Ok((magic_url, StatusCode::from_u16(status).unwrap()));
@@ -322,16 +768,23 @@ async fn resolve_magic_url(
         Vec::new()
     };
 
-    magic_fetch_and_cache(url, magic, attempts).await
+    Span::current().record("outcome", "cache_miss");
+    coalesce(url.clone(), &magic_locks, || {
+        magic_fetch_and_cache(url.clone(), magic.clone(), attempts, http_client, metrics)
+    })
+    .await
 }
 
+#[tracing::instrument(skip_all, fields(url_hash = %url_hash_hex(&url)))]
 async fn magic_fetch_and_cache(
     url: String,
     magic: MagicCache,
     mut attempts: Vec<DateTime<Utc>>,
+    http_client: reqwest::Client,
+    metrics: SharedMetrics,
 ) -> Result<(String, StatusCode), FetchError> {
-    let res = fetch_magic_url(url.clone()).await;
-    info!(target: "cache", "Caching magic {}", url);
+    let res = fetch_magic_url(url.clone(), http_client, metrics).await;
+    tracing::info!("caching fetched magic url");
     match res {
         Ok((magic_url, status)) => {
             let time = Utc::now();
@@ -376,15 +829,20 @@ fn cache_and_return(imgs: ImgCache, saved_image: SavedImage) -> Image {
     saved_image.image
 }
 
-async fn fetch_img(url: String) -> Result<Image, FetchError> {
-    info!(target: "fetch", "Fetching {}", url);
-    let client = reqwest::Client::new();
-    let response = client
+#[tracing::instrument(skip_all, fields(url_hash = %url_hash_hex(&url)))]
+async fn fetch_img(
+    url: String,
+    http_client: reqwest::Client,
+    metrics: SharedMetrics,
+) -> Result<Image, FetchError> {
+    tracing::info!("fetching from upstream");
+    let response = http_client
         .get(&url)
         .header(REFERER, env::var_os("REFERER").unwrap().to_str().unwrap())
         .send()
         .await
         .map_err(|_e| FetchError::RequestFailed)?;
+    metrics.record_upstream_status(response.status().as_u16());
     if !response.status().is_success() {
         return Err(FetchError::Status(response.status()));
     }
@@ -406,16 +864,21 @@ async fn fetch_img(url: String) -> Result<Image, FetchError> {
     })
 }
 
-async fn fetch_magic_url(url: String) -> Result<(String, StatusCode), FetchError> {
-    info!(target: "fetch", "Fetching magic url {}", url);
-    let client = reqwest::Client::new();
-    let response = client
+#[tracing::instrument(skip_all, fields(url_hash = %url_hash_hex(&url)))]
+async fn fetch_magic_url(
+    url: String,
+    http_client: reqwest::Client,
+    metrics: SharedMetrics,
+) -> Result<(String, StatusCode), FetchError> {
+    tracing::info!("fetching magic url from upstream");
+    let response = http_client
         .get(&url)
         .header(REFERER, env::var_os("REFERER").unwrap().to_str().unwrap())
         .send()
         .await
         .map_err(|_e| FetchError::RequestFailed)?;
     let status = response.status();
+    metrics.record_upstream_status(status.as_u16());
     if !response.status().is_success() {
         return Err(FetchError::Status(response.status()));
     }
@@ -436,17 +899,112 @@ async fn fetch_magic_url(url: String) -> Result<(String, StatusCode), FetchError
     Ok((text, status))
 }
 
-fn read_from_disk(pair: &ImgPair) -> Option<SavedImage> {
+fn read_from_disk(
+    pair: &ImgPair,
+    disk_index: &DiskIndex,
+    cache_cipher: &CacheCipher,
+) -> Option<SavedImage> {
     let (_dir, path) = pair_to_path(&pair);
     let mut file = match File::open(&path) {
         Ok(file) => file,
         Err(_e) => return None,
     };
     let mut buf = Vec::new();
-    if file.read_to_end(&mut buf).is_ok() {
-        SavedImage::try_from_slice(&buf).ok()
-    } else {
-        None
+    if file.read_to_end(&mut buf).is_err() {
+        return None;
+    }
+    let plaintext = match cache_cipher {
+        Some(cipher) => {
+            if buf.len() < SECRETBOX_NONCE_LEN {
+                return None;
+            }
+            let (nonce, ciphertext) = buf.split_at(SECRETBOX_NONCE_LEN);
+            match cipher.decrypt(Nonce::from_slice(nonce), ciphertext) {
+                Ok(plaintext) => plaintext,
+                Err(_e) => return None,
+            }
+        }
+        None => buf,
+    };
+    let saved_image = SavedImage::try_from_slice(&plaintext).ok();
+    if saved_image.is_some() {
+        if let Some(entry) = disk_index.lock().unwrap().get_mut(&path) {
+            entry.last_access = Utc::now();
+        }
+    }
+    saved_image
+}
+
+/// Walks [`CACHE_DIR`] building a fresh last-access index, used to seed the
+/// janitor on startup without trusting any previously persisted index.
+fn scan_disk_cache() -> HashMap<String, DiskCacheEntry> {
+    let mut index = HashMap::new();
+    walk_cache_dir(std::path::Path::new(CACHE_DIR), &mut index);
+    index
+}
+
+fn walk_cache_dir(dir: &std::path::Path, index: &mut HashMap<String, DiskCacheEntry>) {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        let Ok(metadata) = entry.metadata() else {
+            continue;
+        };
+        if metadata.is_dir() {
+            walk_cache_dir(&path, index);
+        } else {
+            let last_access = metadata
+                .modified()
+                .map(DateTime::<Utc>::from)
+                .unwrap_or_else(|_e| Utc::now());
+            index.insert(
+                path.to_string_lossy().to_string(),
+                DiskCacheEntry {
+                    size: metadata.len(),
+                    last_access,
+                },
+            );
+        }
+    }
+}
+
+/// Background task that periodically evicts the coldest files under
+/// [`CACHE_DIR`] once the on-disk budget is exceeded. A no-op when
+/// `MAX_DISK_CACHE_BYTES` isn't configured.
+async fn disk_cache_janitor(disk_index: DiskIndex) {
+    let max_bytes = max_bytes_from_env("MAX_DISK_CACHE_BYTES");
+    if max_bytes == 0 {
+        return;
+    }
+    loop {
+        tokio::time::sleep(std::time::Duration::from_secs(DISK_JANITOR_INTERVAL_SECONDS)).await;
+        evict_cold_disk_entries(&disk_index, max_bytes);
+    }
+}
+
+fn evict_cold_disk_entries(disk_index: &DiskIndex, max_bytes: u64) {
+    let mut index = disk_index.lock().unwrap();
+    let mut total_bytes: u64 = index.values().map(|entry| entry.size).sum();
+    if total_bytes <= max_bytes {
+        return;
+    }
+    let mut by_last_access: Vec<(String, DateTime<Utc>)> = index
+        .iter()
+        .map(|(path, entry)| (path.clone(), entry.last_access))
+        .collect();
+    by_last_access.sort_by_key(|(_path, last_access)| *last_access);
+    for (path, _last_access) in by_last_access {
+        if total_bytes <= max_bytes {
+            break;
+        }
+        if std::fs::remove_file(&path).is_ok() {
+            if let Some(entry) = index.remove(&path) {
+                total_bytes = total_bytes.saturating_sub(entry.size);
+            }
+            info!(target: "cache", "Evicted cold disk cache entry {}", path);
+        }
     }
 }
 
@@ -459,15 +1017,49 @@ fn pair_to_path(pair: &ImgPair) -> (String, String) {
     (dir, path)
 }
 
-fn write_to_disk(pair: ImgPair, image: Image) -> Result<SavedImage, std::io::Error> {
+#[tracing::instrument(
+    skip_all,
+    fields(img_type = ?pair.0, url_hash = %url_hash_hex(&pair.1), outcome = field::Empty)
+)]
+fn write_to_disk(
+    pair: ImgPair,
+    image: Image,
+    disk_index: &DiskIndex,
+    cache_cipher: &CacheCipher,
+) -> Result<SavedImage, std::io::Error> {
     let (dir, path) = pair_to_path(&pair);
     std::fs::create_dir_all(dir)?;
-    let mut file = File::create(path).unwrap();
+    let mut file = File::create(&path).unwrap();
     let saved_image = SavedImage {
         image,
         pair,
         time_nanos: Utc::now().timestamp_nanos(),
     };
-    file.write_all(&saved_image.try_to_vec().unwrap())?;
+    let plaintext = saved_image.try_to_vec().unwrap();
+    let bytes = match cache_cipher {
+        Some(cipher) => {
+            let nonce = XSalsa20Poly1305::generate_nonce(&mut OsRng);
+            let ciphertext = cipher
+                .encrypt(&nonce, plaintext.as_slice())
+                .expect("Failed to encrypt cache entry");
+            let mut out = nonce.to_vec();
+            out.extend_from_slice(&ciphertext);
+            Span::current().record("outcome", "encrypted");
+            out
+        }
+        None => {
+            Span::current().record("outcome", "plaintext");
+            plaintext
+        }
+    };
+    file.write_all(&bytes)?;
+    tracing::info!("wrote cache entry to disk");
+    disk_index.lock().unwrap().insert(
+        path,
+        DiskCacheEntry {
+            size: bytes.len() as u64,
+            last_access: Utc::now(),
+        },
+    );
     Ok(saved_image)
 }
@@ -0,0 +1,94 @@
+//! Hand-rolled Prometheus text-exposition-format counters, exported on the
+//! `/metrics` route. The proxy only needs a handful of counters, which
+//! doesn't warrant pulling in a full Prometheus client crate.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// Counters tracked across the lifetime of the process, shared by every
+/// request handler via an `Arc`.
+#[derive(Default)]
+pub struct Metrics {
+    cache_hits: AtomicU64,
+    cache_misses: AtomicU64,
+    disk_hits: AtomicU64,
+    backoffs: AtomicU64,
+    upstream_statuses: Mutex<HashMap<u16, u64>>,
+}
+
+pub type SharedMetrics = Arc<Metrics>;
+
+impl Metrics {
+    pub fn record_cache_hit(&self) {
+        self.cache_hits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_cache_miss(&self) {
+        self.cache_misses.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_disk_hit(&self) {
+        self.disk_hits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_backoff(&self) {
+        self.backoffs.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_upstream_status(&self, status: u16) {
+        *self
+            .upstream_statuses
+            .lock()
+            .unwrap()
+            .entry(status)
+            .or_insert(0) += 1;
+    }
+
+    /// Renders all counters in Prometheus text-exposition format.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        push_counter(
+            &mut out,
+            "img_proxy_cache_hits_total",
+            "In-memory cache hits.",
+            self.cache_hits.load(Ordering::Relaxed),
+        );
+        push_counter(
+            &mut out,
+            "img_proxy_cache_misses_total",
+            "Requests with no cached image in memory or on disk, which required an upstream fetch.",
+            self.cache_misses.load(Ordering::Relaxed),
+        );
+        push_counter(
+            &mut out,
+            "img_proxy_disk_hits_total",
+            "On-disk cache hits.",
+            self.disk_hits.load(Ordering::Relaxed),
+        );
+        push_counter(
+            &mut out,
+            "img_proxy_backoff_total",
+            "Requests short-circuited by the failed-attempt backoff.",
+            self.backoffs.load(Ordering::Relaxed),
+        );
+
+        out.push_str("# HELP img_proxy_upstream_status_total Upstream responses by status code.\n");
+        out.push_str("# TYPE img_proxy_upstream_status_total counter\n");
+        let statuses = self.upstream_statuses.lock().unwrap();
+        let mut statuses: Vec<(u16, u64)> = statuses.iter().map(|(s, c)| (*s, *c)).collect();
+        statuses.sort_by_key(|(status, _)| *status);
+        for (status, count) in statuses {
+            out.push_str(&format!(
+                "img_proxy_upstream_status_total{{status=\"{status}\"}} {count}\n"
+            ));
+        }
+        out
+    }
+}
+
+fn push_counter(out: &mut String, name: &str, help: &str, value: u64) {
+    out.push_str(&format!("# HELP {name} {help}\n"));
+    out.push_str(&format!("# TYPE {name} counter\n"));
+    out.push_str(&format!("{name} {value}\n"));
+}
@@ -0,0 +1,144 @@
+//! Signed-token access control, gating `img_type`/URL pairs behind an HMAC
+//! so the proxy can't be used as an open relay for arbitrary URLs.
+//!
+//! A token is `base64url(borsh(TokenPayload) || hmac_sha256(secret, borsh(TokenPayload)))`.
+//! Verification re-derives the HMAC over the decoded payload and rejects on
+//! any mismatch, an `img_type`/`url` that doesn't match the request, or an
+//! expiry in the past.
+
+use borsh::{BorshDeserialize, BorshSerialize};
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+type HmacSha256 = Hmac<Sha256>;
+
+#[derive(Debug, BorshSerialize, BorshDeserialize)]
+struct TokenPayload {
+    img_type: String,
+    url: String,
+    expires_at: i64,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum TokenError {
+    Malformed,
+    BadSignature,
+    Expired,
+}
+
+/// Signs a token binding `img_type` and `url`, expiring at `expires_at`.
+///
+/// Tokens are minted out-of-band (by whatever service hands out URLs to
+/// clients) using the same `TOKEN_SIGNING_SECRET`; this binary only verifies
+/// them. Kept here, rather than in a separate crate, so the token format
+/// lives next to the code that checks it. Exercised directly by the tests
+/// below; `#[allow(dead_code)]` silences the warning from no in-crate caller.
+#[allow(dead_code)]
+pub fn sign(secret: &[u8], img_type: &str, url: &str, expires_at: DateTime<Utc>) -> String {
+    let payload = TokenPayload {
+        img_type: img_type.to_string(),
+        url: url.to_string(),
+        expires_at: expires_at.timestamp(),
+    };
+    let mut token = payload.try_to_vec().unwrap();
+    let signature = hmac_sign(secret, &token);
+    token.extend_from_slice(&signature);
+    base64::encode_config(token, base64::URL_SAFE_NO_PAD)
+}
+
+/// Verifies that `token` authenticates `img_type` and `url` and hasn't
+/// expired yet.
+pub fn verify(secret: &[u8], token: &str, img_type: &str, url: &str) -> Result<(), TokenError> {
+    let bytes = base64::decode_config(token, base64::URL_SAFE_NO_PAD)
+        .map_err(|_e| TokenError::Malformed)?;
+    if bytes.len() <= 32 {
+        return Err(TokenError::Malformed);
+    }
+    let (payload_bytes, signature) = bytes.split_at(bytes.len() - 32);
+    if !hmac_verify(secret, payload_bytes, signature) {
+        return Err(TokenError::BadSignature);
+    }
+    let payload =
+        TokenPayload::try_from_slice(payload_bytes).map_err(|_e| TokenError::Malformed)?;
+    if payload.img_type != img_type || payload.url != url {
+        return Err(TokenError::BadSignature);
+    }
+    if Utc::now().timestamp() > payload.expires_at {
+        return Err(TokenError::Expired);
+    }
+    Ok(())
+}
+
+/// Only called from `sign`, which is itself only exercised by out-of-band
+/// token minting and the tests below; see the `#[allow(dead_code)]` note on
+/// `sign`.
+#[allow(dead_code)]
+fn hmac_sign(secret: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = HmacSha256::new_from_slice(secret).expect("HMAC accepts a key of any size");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+fn hmac_verify(secret: &[u8], data: &[u8], signature: &[u8]) -> bool {
+    let mut mac = HmacSha256::new_from_slice(secret).expect("HMAC accepts a key of any size");
+    mac.update(data);
+    mac.verify_slice(signature).is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+
+    const SECRET: &[u8] = b"test-secret";
+
+    #[test]
+    fn valid_token_verifies() {
+        let token = sign(SECRET, "thumbnail", "example.com/a.png", Utc::now() + Duration::hours(1));
+        assert_eq!(
+            verify(SECRET, &token, "thumbnail", "example.com/a.png"),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn expired_token_is_rejected() {
+        let token = sign(SECRET, "thumbnail", "example.com/a.png", Utc::now() - Duration::hours(1));
+        assert_eq!(
+            verify(SECRET, &token, "thumbnail", "example.com/a.png"),
+            Err(TokenError::Expired)
+        );
+    }
+
+    #[test]
+    fn token_rebound_to_a_different_url_is_rejected() {
+        let token = sign(SECRET, "thumbnail", "example.com/a.png", Utc::now() + Duration::hours(1));
+        assert_eq!(
+            verify(SECRET, &token, "thumbnail", "example.com/b.png"),
+            Err(TokenError::BadSignature)
+        );
+    }
+
+    #[test]
+    fn tampered_signature_is_rejected() {
+        let token = sign(SECRET, "thumbnail", "example.com/a.png", Utc::now() + Duration::hours(1));
+        let mut bytes = base64::decode_config(&token, base64::URL_SAFE_NO_PAD).unwrap();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 0xff;
+        let tampered = base64::encode_config(bytes, base64::URL_SAFE_NO_PAD);
+        assert_eq!(
+            verify(SECRET, &tampered, "thumbnail", "example.com/a.png"),
+            Err(TokenError::BadSignature)
+        );
+    }
+
+    #[test]
+    fn wrong_secret_is_rejected() {
+        let token = sign(SECRET, "thumbnail", "example.com/a.png", Utc::now() + Duration::hours(1));
+        assert_eq!(
+            verify(b"other-secret", &token, "thumbnail", "example.com/a.png"),
+            Err(TokenError::BadSignature)
+        );
+    }
+}